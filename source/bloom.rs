@@ -0,0 +1,75 @@
+//! A small counting Bloom filter used to cheaply reject ancestor
+//! selectors while matching descendant/child combinators.
+//!
+//! `style_tree` maintains one of these as it walks down the DOM tree,
+//! inserting each element's id, classes and tag name on the way down
+//! and removing them again on the way back up. Before attempting the
+//! (potentially expensive, backtracking) ancestor walk for a compound
+//! selector, we can query the filter for the ancestor-side selector's
+//! required atoms: if any of them is definitely absent from every
+//! ancestor currently on the path, the selector cannot match and the
+//! walk can be skipped entirely.
+
+const NUM_BITS: uint = 2048;
+const NUM_HASHES: uint = 2;
+
+/// A fixed-size counting Bloom filter. Each slot stores a small count
+/// (rather than a single bit) so that removing an atom is exact even
+/// when two different atoms happen to hash to the same slot.
+pub struct BloomFilter {
+	counts: Vec<u8>,
+}
+
+impl BloomFilter {
+	pub fn new() -> BloomFilter {
+		BloomFilter { counts: Vec::from_elem(NUM_BITS, 0u8) }
+	}
+
+	/// Insert an atom (an id, class name, or tag name) into the filter.
+	pub fn insert(&mut self, atom: &str) {
+		for slot in hash_slots(atom).iter() {
+			self.counts[*slot] = self.counts[*slot].saturating_add(1);
+		}
+	}
+
+	/// Remove an atom that was previously inserted. Each slot the atom
+	/// hashes to is decremented unless it is already zero, so removing
+	/// an atom that was never inserted also lowers the counts of any
+	/// other atoms sharing those slots.
+	pub fn remove(&mut self, atom: &str) {
+		for slot in hash_slots(atom).iter() {
+			if self.counts[*slot] > 0 {
+				self.counts[*slot] -= 1;
+			}
+		}
+	}
+
+	/// Returns true if `atom` is *definitely* not present in the filter.
+	/// Returns false if it is *probably* present (it may still be a
+	/// false positive, in which case the caller must fall back to the
+	/// exact check).
+	pub fn definitely_absent(&self, atom: &str) -> bool {
+		hash_slots(atom).iter().any(|slot| self.counts[*slot] == 0)
+	}
+}
+
+/// Two independent FNV-1a hashes (different seeds), combined by double
+/// hashing to produce `NUM_HASHES` slot indices.
+fn hash_slots(atom: &str) -> [uint, ..NUM_HASHES] {
+	let h1 = fnv1a(atom, 0x811c9dc5u32) as uint;
+	let h2 = fnv1a(atom, 0x01000193u32) as uint;
+	let mut slots = [0u, ..NUM_HASHES];
+	for i in range(0u, NUM_HASHES) {
+		slots[i] = (h1 + i * h2) % NUM_BITS;
+	}
+	slots
+}
+
+fn fnv1a(atom: &str, seed: u32) -> u32 {
+	let mut hash = seed;
+	for byte in atom.bytes() {
+		hash = hash ^ (byte as u32);
+		hash = hash * 0x01000193u32;
+	}
+	hash
+}
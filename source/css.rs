@@ -12,13 +12,44 @@ use std::num::FromStrRadix;
 A simple selector can include a tag name, an ID prefixed by '#', any number of class names prefixed by '.', or some combination of the above. If the tag name is empty or '*' then it is a “universal selector” that can match any tag.
 */
 
-// A CSS stylesheet is a series of rules
+// A CSS stylesheet is a series of items: plain qualified rules, or
+// at-rules like @import and @media.
 struct Stylesheet {
+	items: Vec<CssItem>,
+}
+
+/// A single recoverable parse error: the byte position it was found
+/// at, and a human-readable message.
+pub struct ParseError {
+	pub pos: uint,
+	pub message: String,
+}
+
+/*
+The grammar so far only covered qualified rules (`<selectors> {
+<declarations> }`). At-rules are anything starting with `@`: an
+at-keyword followed by a prelude, then either a `;` (a "statement"
+at-rule, e.g. `@import`) or a `{ ... }` block (a "block" at-rule,
+e.g. `@media`).
+ */
+enum CssItem {
+	Qualified(Rule),
+	// `@import url("foo.css");` — the URL as written in the prelude,
+	// with `url(...)` and quotes stripped. Resolving it and splicing the
+	// imported stylesheet's rules in is left to the caller.
+	Import(String),
+	Media(MediaRule),
+}
+
+/// `@media <query> { <rules> }`. The block is just a nested list of
+/// qualified rules; nested at-rules aren't supported.
+struct MediaRule {
+	query: String,
 	rules: Vec<Rule>,
 }
 
 /*
-A rule includes one or more selectors separated by commas, 
+A rule includes one or more selectors separated by commas,
 followed by a series of declarations enclosed in braces
  */
 struct Rule {
@@ -27,18 +58,71 @@ struct Rule {
 }
 
 /*
-A selector can be a simple selector or it can be a chain of 
-selectors joined by combinators. Robinson supports only simple
-selectors for now.
+A selector can be a simple selector, or a chain of simple
+selectors joined by combinators (e.g. `div.note p` or `ul > li`).
+Compound selectors are matched right-to-left: the rightmost part
+is tested against the element itself, and the earlier parts are
+tested against its ancestors.
  */
 enum Selector {
 	Simple(SimpleSelector),
+	Compound {
+		// `parts` are stored left-to-right, e.g. [div, .note, p]
+		// for `div.note p`. `combinators[i]` is the combinator
+		// that joins `parts[i]` to `parts[i+1]`.
+		parts: Vec<SimpleSelector>,
+		combinators: Vec<Combinator>,
+	},
+}
+
+/// A combinator joins two simple selectors in a compound selector.
+enum Combinator {
+	Descendant, // `A B`: B is an arbitrary descendant of A
+	Child,      // `A > B`: B is a direct child of A
 }
 
 struct SimpleSelector {
 	tag_name: Option<String>,
 	id: Option<String>,
 	class: Vec<String>,
+	attrs: Vec<AttrSelector>,
+	pseudo: Vec<PseudoClass>,
+}
+
+/*
+Structural pseudo-classes depend on an element's position among its
+siblings rather than anything on the element itself, so matching them
+needs the element's 1-based index among its element siblings (and, for
+`:last-child`, the total number of element siblings). `:nth-child(an+b)`
+matches when there exists a non-negative integer `n` with
+`index == a*n + b`; `odd`/`even` are shorthand for `2n+1`/`2n`.
+ */
+enum PseudoClass {
+	FirstChild,
+	LastChild,
+	NthChild { a: i32, b: i32 },
+}
+
+/*
+An attribute selector tests a single attribute on an element:
+presence (`[attr]`), an exact value (`[attr=val]`), whitespace-list
+membership (`[attr~=val]`, as used for matching one class among
+several), or a prefix/suffix/substring match (`[attr^=val]`,
+`[attr$=val]`, `[attr*=val]`).
+ */
+struct AttrSelector {
+	name: String,
+	op: AttrOp,
+	value: Option<String>,
+}
+
+enum AttrOp {
+	Presence,  // [attr]
+	Exact,     // [attr=val]
+	Includes,  // [attr~=val]
+	Prefix,    // [attr^=val]
+	Suffix,    // [attr$=val]
+	Substring, // [attr*=val]
 }
 
 /*
@@ -78,41 +162,316 @@ struct Color {
 
 /*
 This project uses a very simplistic parser built the
-same way as the HTML parser was. 
+same way as the HTML parser was.
  */
 
-/// Parse one simple selector, e.g: `type#id.class1.class2`
-fn parse_simple_selector(&mut self) -> SimpleSelector {
-    let mut selector = SimpleSelector { tag_name: None, id: None, class: Vec::new() };
+struct Parser {
+	pos: uint,
+	input: String,
+	errors: Vec<ParseError>,
+}
+
+/// Parse one simple selector, e.g: `type#id.class1.class2[attr~=val]:first-child`.
+/// Returns `None` if any part of it was malformed (a `#` or `.` with no
+/// identifier after it, or a bad attribute selector or pseudo-class):
+/// the error has already been recorded and the position already synced
+/// forward, so the
+/// whole simple selector (and therefore the rule it's part of) is
+/// discarded rather than kept with the bad piece silently dropped.
+fn parse_simple_selector(&mut self) -> Option<SimpleSelector> {
+    let mut selector = SimpleSelector { tag_name: None, id: None, class: Vec::new(), attrs: Vec::new(), pseudo: Vec::new() };
     while !self.eof() {
     	match self.next_char() {
     		'#' => {
+    			let start = self.pos;
     			self.consume_char();
-    			selector.id = Some(self.parse_identifier());
+    			let id = self.parse_identifier();
+    			if id.is_empty() {
+    				self.errors.push(ParseError {
+    					pos: start,
+    					message: format!("Expected an identifier after '#'")
+    				});
+    				self.recover_to_selector_sync();
+    				return None;
+    			}
+    			selector.id = Some(id);
     		}
     		'.' => {
+    			let start = self.pos;
     			self.consume_char();
-    			selector.class.push(self.parse_identifier());
+    			let class = self.parse_identifier();
+    			if class.is_empty() {
+    				self.errors.push(ParseError {
+    					pos: start,
+    					message: format!("Expected an identifier after '.'")
+    				});
+    				self.recover_to_selector_sync();
+    				return None;
+    			}
+    			selector.class.push(class);
     		}
     		'*' => {
     			// universal selector
     			self.consume_char();
     		}
+    		'[' => {
+    			match self.parse_attr_selector() {
+    				Some(attr) => selector.attrs.push(attr),
+    				None => return None,
+    			}
+    		}
+    		':' => {
+    			self.consume_char();
+    			match self.parse_pseudo_class() {
+    				Some(pseudo) => selector.pseudo.push(pseudo),
+    				None => return None,
+    			}
+    		}
     		c if valid_identifier_char(c) => {
     			selector.tag_name = Some(self.parse_identifier());
     		}
     		_ => break
     	}
     }
-    return selector;
+    Some(selector)
 }
 
 /*
-There's a lack of error checking. Some malformed input like ### or *foo* successfully and produce weird results. A real CSS parser would discard selectors.
+A structural pseudo-class is `:` followed by an identifier, and for
+`:nth-child` a parenthesized `an+b` expression (or the `odd`/`even`
+keywords).
+ */
+
+/// Parse a single pseudo-class, after the leading `:` has been consumed.
+/// Returns `None` (after recording a `ParseError` and syncing forward)
+/// for an unrecognized pseudo-class or malformed `nth-child(...)` syntax,
+/// same as any other malformed selector.
+fn parse_pseudo_class(&mut self) -> Option<PseudoClass> {
+	let start = self.pos;
+	let name = self.parse_identifier();
+	match name.as_slice() {
+		"first-child" => Some(PseudoClass::FirstChild),
+		"last-child" => Some(PseudoClass::LastChild),
+		"nth-child" => {
+			if self.eof() || self.next_char() != '(' {
+				self.errors.push(ParseError {
+					pos: start,
+					message: format!("Expected '(' after :nth-child")
+				});
+				self.recover_to_selector_sync();
+				return None;
+			}
+			self.consume_char();
+			let (a, b) = self.parse_nth_child_expr();
+			if self.eof() || self.next_char() != ')' {
+				self.errors.push(ParseError {
+					pos: start,
+					message: format!("Expected ')' to close :nth-child(...)")
+				});
+				self.recover_to_selector_sync();
+				return None;
+			}
+			self.consume_char();
+			Some(PseudoClass::NthChild { a: a, b: b })
+		}
+		_ => {
+			self.errors.push(ParseError {
+				pos: start,
+				message: format!("Unsupported pseudo-class :{}", name)
+			});
+			self.recover_to_selector_sync();
+			None
+		}
+	}
+}
+
+/// Parse an `an+b` expression, or the `odd`/`even` keywords.
+fn parse_nth_child_expr(&mut self) -> (i32, i32) {
+	self.consume_whitespace();
+	if self.starts_with("odd") {
+		self.pos += 3;
+		return (2, 1);
+	}
+	if self.starts_with("even") {
+		self.pos += 4;
+		return (2, 0);
+	}
+
+	let sign: i32 = if self.next_char() == '-' { self.consume_char(); -1 } else { 1 };
+	let digits = self.consume_while(|c| c.is_digit());
+
+	if !self.eof() && self.next_char() == 'n' {
+		self.consume_char();
+		let coeff: i32 = if digits.len() > 0 { FromStr::from_str(digits.as_slice()).unwrap_or(1i32) } else { 1 };
+		let a = sign * coeff;
+
+		self.consume_whitespace();
+		let b = if !self.eof() && (self.next_char() == '+' || self.next_char() == '-') {
+			let b_sign: i32 = if self.consume_char() == '-' { -1 } else { 1 };
+			self.consume_whitespace();
+			let b_digits = self.consume_while(|c| c.is_digit());
+			b_sign * FromStr::from_str(b_digits.as_slice()).unwrap_or(0i32)
+		} else {
+			0
+		};
+
+		(a, b)
+	} else {
+		// No `n`: the whole expression is just a constant, e.g. `:nth-child(3)`.
+		let value: i32 = FromStr::from_str(digits.as_slice()).unwrap_or(0i32);
+		(0, sign * value)
+	}
+}
+
+/*
+An attribute selector is `[` an identifier, an optional operator
+(`=`, `~=`, `^=`, `$=`, `*=`) and value, then `]`. The value may be
+quoted (like an attribute value in HTML) or a bare identifier.
+ */
+
+/// Parse a single `[...]` attribute selector. Returns `None` (after
+/// recording a `ParseError` and syncing forward) for a missing `]`, a
+/// bad operator, or any other malformed `[attr...]` syntax, same as
+/// any other malformed selector.
+fn parse_attr_selector(&mut self) -> Option<AttrSelector> {
+	let start = self.pos;
+	self.consume_char(); // '['
+	self.consume_whitespace();
+	let name = self.parse_identifier();
+	self.consume_whitespace();
+
+	if !self.eof() && self.next_char() == ']' {
+		self.consume_char();
+		return Some(AttrSelector { name: name, op: AttrOp::Presence, value: None });
+	}
+
+	if self.eof() {
+		self.errors.push(ParseError {
+			pos: start,
+			message: format!("Unterminated attribute selector")
+		});
+		self.recover_to_selector_sync();
+		return None;
+	}
+
+	let first = self.consume_char();
+	let maybe_op = match first {
+		'=' => Some(AttrOp::Exact),
+		'~' => Some(AttrOp::Includes),
+		'^' => Some(AttrOp::Prefix),
+		'$' => Some(AttrOp::Suffix),
+		'*' => Some(AttrOp::Substring),
+		_ => None
+	};
+	let op = match maybe_op {
+		Some(AttrOp::Exact) => AttrOp::Exact,
+		Some(op) => {
+			if self.eof() || self.consume_char() != '=' {
+				self.errors.push(ParseError {
+					pos: start,
+					message: format!("Expected '=' after '{}' in attribute selector", first)
+				});
+				self.recover_to_selector_sync();
+				return None;
+			}
+			op
+		}
+		None => {
+			self.errors.push(ParseError {
+				pos: start,
+				message: format!("Unexpected character '{}' in attribute selector", first)
+			});
+			self.recover_to_selector_sync();
+			return None;
+		}
+	};
+
+	self.consume_whitespace();
+	let value = match self.next_char() {
+		'"' | '\'' => self.parse_attr_selector_value(),
+		_ => self.parse_identifier(),
+	};
+	self.consume_whitespace();
+	if self.eof() || self.consume_char() != ']' {
+		self.errors.push(ParseError {
+			pos: start,
+			message: format!("Expected closing ']' in attribute selector")
+		});
+		self.recover_to_selector_sync();
+		return None;
+	}
+
+	Some(AttrSelector { name: name, op: op, value: Some(value) })
+}
+
+/// Parse a quoted attribute selector value, e.g. `"text"`.
+fn parse_attr_selector_value(&mut self) -> String {
+	let open_quote = self.consume_char();
+	let value = self.consume_while(|c| c != open_quote);
+	self.consume_char(); // closing quote
+	return value;
+}
+
+/*
+A selector list is a comma-separated list of (possibly compound)
+selectors. Each individual selector is parsed by `parse_selector`,
+which keeps consuming simple selectors joined by combinators until
+it hits a `,` or the `{` that starts the declaration block.
+ */
+
+/// Parse a single selector, which may be a chain of simple selectors
+/// joined by descendant or child combinators. Returns `None` if any
+/// part of the chain was malformed, propagated from
+/// `parse_simple_selector` so the whole selector is discarded.
+fn parse_selector(&mut self) -> Option<Selector> {
+	let mut parts = Vec::new();
+	match self.parse_simple_selector() {
+		Some(simple) => parts.push(simple),
+		None => return None,
+	}
+	let mut combinators = Vec::new();
+
+	loop {
+		self.consume_whitespace();
+		match self.next_char() {
+			',' | '{' => break,
+			'>' => {
+				self.consume_char();
+				self.consume_whitespace();
+				combinators.push(Combinator::Child);
+				match self.parse_simple_selector() {
+					Some(simple) => parts.push(simple),
+					None => return None,
+				}
+			}
+			c if valid_identifier_char(c) || c == '#' || c == '.' || c == '*' || c == '[' || c == ':' => {
+				// Whitespace followed by another simple selector means
+				// a descendant combinator.
+				combinators.push(Combinator::Descendant);
+				match self.parse_simple_selector() {
+					Some(simple) => parts.push(simple),
+					None => return None,
+				}
+			}
+			_ => break
+		}
+	}
+
+	if parts.len() == 1 {
+		Some(Selector::Simple(parts.into_iter().next().unwrap()))
+	} else {
+		Some(Selector::Compound { parts: parts, combinators: combinators })
+	}
+}
+
+/*
+Malformed input like `###` (a `#`/`.` with no identifier after it) is
+now rejected by `parse_simple_selector`, which discards the whole
+selector rather than producing a weird `id: Some("")`.
 
 Specificity is one of the ways a rendering engine decides which style overrides the other in a conflict. If a stylesheet contains two rules that match an element, the rule with the matching selector of higher specificity can override values from the one with lower specificity.
 
-The specificity of a selector is based on its components. An ID selector is more specific than a class selector, which is more specific than a tag selector. Within each of these "levels" more selectors beats fewer.
+The specificity of a selector is based on its components. An ID selector is more specific than a class selector, which is more specific than a tag selector. Within each of these "levels" more selectors beats fewer. A compound selector's specificity is the sum of the specificities of each of its simple selectors, per the W3C spec.
  */
 
 pub type Specificity = (uint, uint, uint);
@@ -120,14 +479,27 @@ pub type Specificity = (uint, uint, uint);
 impl Selector {
 	pub fn specificity(&self) -> Specificity {
 		 // http://www.w3.org/TR/selectors/#specificity
-		 let Selector::Simple(ref simple) = *self;
-		 let a = simple.id.iter().len();
-		 let b = simple.class.len();
-		 let c = simple.tag_name.iter().len();
-		 (a, b, c)
+		 match *self {
+		 	Selector::Simple(ref simple) => simple_specificity(simple),
+		 	Selector::Compound { ref parts, .. } => {
+		 		parts.iter().fold((0, 0, 0), |(a, b, c), simple| {
+		 			let (sa, sb, sc) = simple_specificity(simple);
+		 			(a + sa, b + sb, c + sc)
+		 		})
+		 	}
+		 }
 	}
 }
 
+fn simple_specificity(simple: &SimpleSelector) -> Specificity {
+	let a = simple.id.iter().len();
+	// Attribute selectors and pseudo-classes both count as class-level
+	// components per the W3C spec.
+	let b = simple.class.len() + simple.attrs.len() + simple.pseudo.len();
+	let c = simple.tag_name.iter().len();
+	(a, b, c)
+}
+
 /*
 The selectors for each rule are stored in a sorted vector, most-specific first. This will be important in matching
  */
@@ -140,17 +512,55 @@ fn parse_rule(&mut self) -> Rule {
 	}
 }
 
+/*
+Malformed input like `div, #id ### { ... }` used to make this `panic!`.
+Instead, a selector that isn't followed by `,` or `{` is discarded: we
+record a `ParseError` and skip forward to the next safe sync point (the
+next `,`, `{`, `}` or `;`) before continuing, same as a real CSS parser
+throwing away one bad selector in a list rather than the whole sheet.
+ */
+
 // Parse a comma-separated list of selectors.
 fn parse_selectors(&mut self) -> Vec<Selector> {
 	let mut selectors = Vec::new();
 	loop {
-	  selectors.push(Selector::Simple(self.parse_simple_selector()));
-	  self.consume_whitespace();
-	  match self.next_char() {
-	  	',' => { self.consume_char(); self.consume_whitespace(); }
-	  	'{' => break, // start of declarations
-	  	c => panic!("Unexpected character {} in selector list", c)
-	  }
+		self.consume_whitespace();
+		if self.eof() || self.next_char() == '{' {
+			break;
+		}
+
+		let start = self.pos;
+		match self.parse_selector() {
+			Some(selector) => {
+				self.consume_whitespace();
+				match self.next_char() {
+					',' => {
+						selectors.push(selector);
+						self.consume_char();
+					}
+					'{' => {
+						selectors.push(selector);
+					}
+					c => {
+						self.errors.push(ParseError {
+							pos: start,
+							message: format!("Unexpected character '{}' in selector list", c)
+						});
+						self.recover_to_selector_sync();
+						// `}` and `;` can't start a selector; step past
+						// them so the next attempt makes progress.
+						if !self.eof() && (self.next_char() == '}' || self.next_char() == ';') {
+							self.consume_char();
+						}
+					}
+				}
+			}
+			None => {
+				// The failing sub-parser already recorded a ParseError
+				// and synced forward to a safe point; nothing more to
+				// do here besides retry from the new position.
+			}
+		}
 	}
 
 	// Return selectors with highest specificity first, for use in matching
@@ -158,34 +568,171 @@ fn parse_selectors(&mut self) -> Vec<Selector> {
 	return selectors;
 }
 
+/// Skip forward to the next `,`, `{`, `}` or `;` — a safe point to
+/// resume parsing after a malformed selector.
+fn recover_to_selector_sync(&mut self) {
+	while !self.eof() {
+		match self.next_char() {
+			',' => { self.consume_char(); break; }
+			'{' | '}' | ';' => break,
+			_ => { self.consume_char(); }
+		}
+	}
+}
 
+/*
+Parsing an item means looking one character ahead: `@` starts an
+at-rule, anything else starts a qualified rule.
+ */
 
+/// Parse a single top-level item: a qualified rule, or an at-rule.
+/// Returns `None` if an at-rule was malformed or unsupported (the
+/// error has already been recorded and the position already synced
+/// forward), so it's discarded rather than kept.
+fn parse_item(&mut self) -> Option<CssItem> {
+	if self.next_char() == '@' {
+		self.parse_at_rule()
+	} else {
+		Some(CssItem::Qualified(self.parse_rule()))
+	}
+}
 
+/// Parse a sequence of top-level items until EOF.
+fn parse_items(&mut self) -> Vec<CssItem> {
+	let mut items = Vec::new();
+	loop {
+		self.consume_whitespace();
+		if self.eof() {
+			break;
+		}
+		match self.parse_item() {
+			Some(item) => items.push(item),
+			None => {}
+		}
+	}
+	return items;
+}
 
+/// Parse a sequence of qualified rules, stopping at `}` or EOF. Used
+/// for the body of a block at-rule like `@media`.
+fn parse_qualified_rules(&mut self) -> Vec<Rule> {
+	let mut rules = Vec::new();
+	loop {
+		self.consume_whitespace();
+		if self.eof() || self.next_char() == '}' {
+			break;
+		}
+		rules.push(self.parse_rule());
+	}
+	return rules;
+}
 
+/// Parse an at-rule: an at-keyword, then a prelude up to `{` or `;`,
+/// then (for block at-rules) a `{ ... }` body. Returns `None` (after
+/// recording a `ParseError` and recovering past the at-rule) for an
+/// unsupported at-keyword or malformed `@import`/`@media` syntax, same
+/// as any other malformed selector.
+///
+/// Only `@import` (a statement at-rule) and `@media` (a block
+/// at-rule) are understood; anything else is an error, same as an
+/// unrecognized selector character.
+fn parse_at_rule(&mut self) -> Option<CssItem> {
+	let start = self.pos;
+	self.consume_char(); // '@'
+	let keyword = self.parse_identifier();
+	self.consume_whitespace();
+	let prelude = self.consume_while(|c| c != '{' && c != ';');
+	let prelude = prelude.as_slice().trim().to_string();
+
+	match keyword.as_slice() {
+		"import" => {
+			if self.eof() || self.next_char() != ';' {
+				self.errors.push(ParseError {
+					pos: start,
+					message: format!("Expected ';' to close @import")
+				});
+				self.recover_at_rule();
+				return None;
+			}
+			self.consume_char();
+			Some(CssItem::Import(parse_url(prelude.as_slice())))
+		}
+		"media" => {
+			if self.eof() || self.next_char() != '{' {
+				self.errors.push(ParseError {
+					pos: start,
+					message: format!("Expected '{{' to open @media block")
+				});
+				self.recover_at_rule();
+				return None;
+			}
+			self.consume_char();
+			self.consume_whitespace();
+			let rules = self.parse_qualified_rules();
+			self.consume_whitespace();
+			if self.eof() || self.next_char() != '}' {
+				self.errors.push(ParseError {
+					pos: start,
+					message: format!("Expected '}}' to close @media block")
+				});
+				self.recover_at_rule();
+				return None;
+			}
+			self.consume_char();
+			Some(CssItem::Media(MediaRule { query: prelude, rules: rules }))
+		}
+		_ => {
+			self.errors.push(ParseError {
+				pos: start,
+				message: format!("Unsupported at-rule @{}", keyword)
+			});
+			self.recover_at_rule();
+			None
+		}
+	}
+}
 
+/// Skip forward past a malformed or unsupported at-rule: a trailing
+/// `;`, or a balanced `{ ... }` block, so a later item can resume
+/// parsing at the next safe boundary.
+fn recover_at_rule(&mut self) {
+	if self.eof() {
+		return;
+	}
+	match self.next_char() {
+		';' => { self.consume_char(); }
+		'{' => {
+			self.consume_char();
+			let mut depth = 1u;
+			while !self.eof() && depth > 0 {
+				match self.consume_char() {
+					'{' => depth += 1,
+					'}' => depth -= 1,
+					_ => {}
+				}
+			}
+		}
+		_ => {}
+	}
+}
 
+/// Pull the quoted (or bare) URL out of an `@import` prelude, which is
+/// either `url("foo.css")`, `url(foo.css)`, or just `"foo.css"`.
+fn parse_url(prelude: &str) -> String {
+	let trimmed = prelude.trim();
+	let inner = if trimmed.starts_with("url(") && trimmed.ends_with(")") {
+		trimmed.slice(4, trimmed.len() - 1).trim()
+	} else {
+		trimmed
+	};
+	inner.trim_matches(|c: char| c == '"' || c == '\'').to_string()
+}
 
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
+/// Parse a whole CSS stylesheet, along with any recoverable errors
+/// encountered along the way (malformed selectors that were discarded
+/// rather than causing the whole sheet to fail to parse).
+pub fn parse(source: String) -> (Stylesheet, Vec<ParseError>) {
+	let mut parser = Parser { pos: 0, input: source, errors: Vec::new() };
+	let items = parser.parse_items();
+	(Stylesheet { items: items }, parser.errors)
+}
@@ -14,14 +14,26 @@ use dom;
 use std::collections::HashMap;
 
 /*
-The parser stores its input string and a 
+The parser stores its input string and a
 current position within the string. The
 position is the index of the next character
-we haven't processed yet.
+we haven't processed yet. `errors` collects
+recoverable parse errors (malformed tags,
+mismatched attributes) so that a caller can
+report them instead of the parser just
+`panic!`ing on bad input.
  */
 struct Parser {
 	pos: uint,
 	input: String,
+	errors: Vec<ParseError>,
+}
+
+/// A single recoverable parse error: the byte position it was found
+/// at, and a human-readable message.
+pub struct ParseError {
+	pub pos: uint,
+	pub message: String,
 }
 
 /*
@@ -100,11 +112,12 @@ fn parse_tag_name(&mut self) -> String {
 /// text node. In our simplified version of HTML, a text node can
 /// contain any character except <.
 
-/// Parse a single node.
-fn parse_node(&mut self) -> dom::Node {
+/// Parse a single node. Returns `None` if the node was malformed and
+/// had to be discarded (the error has already been recorded).
+fn parse_node(&mut self) -> Option<dom::Node> {
 	match self.next_char() {
 		'<' => self.parse_element(),
-		_   => self.parse_text()
+		_   => Some(self.parse_text())
 	}
 }
 
@@ -116,59 +129,130 @@ fn parse_text(&mut self) -> dom::Node {
 /*
 An element is more complicated. It includes opening and closing tags and between them any
 number of child nodes
+
+The `assert!`s that used to guard each of these characters would
+`panic!` on malformed markup such as a missing `>` or a mismatched
+closing tag. Instead, `expect_char` records a `ParseError` and skips
+forward to the next `<` or `>` (our sync points for HTML) so the
+parser can keep going; the element being parsed is then discarded by
+returning `None` rather than a `dom::Node` built from garbage.
  */
 
 /// Parse a single element, incluidng its open
-/// tag, contents and closing tag
-fn parse_element(&mut self) -> dom::Node {
+/// tag, contents and closing tag. Returns `None` if the element was
+/// malformed and had to be discarded.
+fn parse_element(&mut self) -> Option<dom::Node> {
 	// Opening tag
-	assert!(self.consume_char() == '<');
+	if !self.expect_char('<') { return None; }
 	let tag_name = self.parse_tag_name();
 	let attrs = self.parse_attributes();
-	assert!(self.consume_char() == '>');
+	if !self.expect_char('>') { return None; }
 
 	// Contents
 	let children = self.parse_nodes();
 
 	// Closing tag
-	assert!(self.consume_char() == '<');
-	assert!(self.consume_char() == '/');
-	assert!(self.parse_tag_name() == tag_name);
-	assert!(self.consume_char() == '>');
+	if !self.expect_char('<') { return None; }
+	if !self.expect_char('/') { return None; }
+	let close_name = self.parse_tag_name();
+	if close_name != tag_name {
+		self.errors.push(ParseError {
+			pos: self.pos,
+			message: format!("Mismatched closing tag: expected </{}>, found </{}>", tag_name, close_name)
+		});
+		self.recover_to_tag_sync();
+		return None;
+	}
+	if !self.expect_char('>') { return None; }
+
+	return Some(dom::elem(tag_name, attrs, children));
+}
 
-	return dom::elem(tag_name, attrs, children);
+/// Consume a character, recording a `ParseError` and recovering to the
+/// next sync point if it isn't `expected`.
+fn expect_char(&mut self, expected: char) -> bool {
+	let pos = self.pos;
+	if self.eof() {
+		self.errors.push(ParseError {
+			pos: pos,
+			message: format!("Expected '{}' but found end of input", expected)
+		});
+		return false;
+	}
+	let found = self.consume_char();
+	if found == expected {
+		true
+	} else {
+		self.errors.push(ParseError {
+			pos: pos,
+			message: format!("Expected '{}' but found '{}'", expected, found)
+		});
+		self.recover_to_tag_sync();
+		false
+	}
+}
+
+/// Skip forward to the next `<` or `>` — a safe point to resume
+/// parsing after malformed markup.
+fn recover_to_tag_sync(&mut self) {
+	while !self.eof() {
+		match self.next_char() {
+			'<' | '>' => break,
+			_ => { self.consume_char(); }
+		}
+	}
 }
 
 /*
 Parsing attributes is quite easy with our simplified syntax. Until we reach the end of the opening tag (>) we repeatedly look for a name, followed by = and then a string enclosed in quotes.
  */
 
-/// Parse a single name="value" pair
-fn parse_attr(&mut self) -> (String, String) {
+/// Parse a single name="value" pair. Returns `None` if the pair was
+/// malformed and had to be discarded.
+fn parse_attr(&mut self) -> Option<(String, String)> {
 	let name = self.parse_tag_name();
-	assert!(self.consume_char() == '=');
-	let value = self.parse_attr_value();
-	return (name, value);
+	if !self.expect_char('=') { return None; }
+	self.parse_attr_value().map(|value| (name, value))
 }
 
-/// Parse a quoted value
-fn parse_attr_value(&mut self) -> String {
+/// Parse a quoted value. Returns `None` if the value didn't start
+/// with a quote or its closing quote is missing.
+fn parse_attr_value(&mut self) -> Option<String> {
+	let pos = self.pos;
+	if self.eof() {
+		self.errors.push(ParseError {
+			pos: pos,
+			message: format!("Expected a quote to start an attribute value, found end of input")
+		});
+		return None;
+	}
 	let open_quote = self.consume_char();
-	assert!(open_quote == '"' || open_quote == '\');
+	if open_quote != '"' && open_quote != '\'' {
+		self.errors.push(ParseError {
+			pos: pos,
+			message: format!("Expected a quote to start an attribute value, found '{}'", open_quote)
+		});
+		self.recover_to_tag_sync();
+		return None;
+	}
 	let value = self.consume_while(|c| c != open_quote);
-	return value;
+	if !self.expect_char(open_quote) { return None; }
+	return Some(value);
 }
 
 /// Parse a list of name="value" pairs, separated by whitespace.
+/// Malformed pairs are discarded (their errors were already recorded).
 fn parse_attributes(&mut self) -> dom::AttrMap {
 	let mut attributes = HashMap::new();
 	loop {
 		self.consume_whitespace();
-		if self.next_char() == '>' {
+		if self.eof() || self.next_char() == '>' {
 			break;
 		}
-		let (name, value) = self.parse_attr();
-		attributes.insert(name, value);
+		match self.parse_attr() {
+			Some((name, value)) => { attributes.insert(name, value); }
+			None => {}
+		}
 	}
 	return attributes;
 }
@@ -177,7 +261,8 @@ fn parse_attributes(&mut self) -> dom::AttrMap {
 To parse the child nodes, we recursively call parse_node in a loop until we reach the closing tag
  */
 
-/// Parse a sequence of sibling nodes
+/// Parse a sequence of sibling nodes. A malformed node is discarded
+/// (its error was already recorded) rather than stored as garbage.
 fn parse_nodes(&mut self) -> Vec<dom::Node> {
 	let mut nodes = Vec::new();
 	loop {
@@ -185,7 +270,10 @@ fn parse_nodes(&mut self) -> Vec<dom::Node> {
 		if self.eof() || self.starts_with("</") {
 			break;
 		}
-		nodes.push(self.parse_node());
+		match self.parse_node() {
+			Some(node) => nodes.push(node),
+			None => {}
+		}
 	}
 	return nodes;
 }
@@ -194,16 +282,20 @@ fn parse_nodes(&mut self) -> Vec<dom::Node> {
 Finally, we can put this all together to parse an entire HTML document into a DOM tree. This function will create a root node for the document if it doesn't include one explicitly. This is similar to what a real HTML parser does.
  */
 
-/// Parse an HTML document and return the root element
-pub fn parse(source: String) -> dom::Node {
-	let mut nodes = Parser { pos:0, input: source}.parse_nodes();
+/// Parse an HTML document and return the root element, along with any
+/// recoverable errors encountered along the way.
+pub fn parse(source: String) -> (dom::Node, Vec<ParseError>) {
+	let mut parser = Parser { pos: 0, input: source, errors: Vec::new() };
+	let mut nodes = parser.parse_nodes();
 
 	// If the document contains a root element, return it. Otherwise create one.
-	if nodes.len() == 1 {
-		nodes.swap_remove(0).unwrap();
+	let root = if nodes.len() == 1 {
+		nodes.swap_remove(0)
 	} else {
-		dom::elem("html".to_string(), HashMap::new(), nodes);
-	}
+		dom::elem("html".to_string(), HashMap::new(), nodes)
+	};
+
+	(root, parser.errors)
 }
 
 
@@ -4,29 +4,135 @@
 //! complicated if I add support for compound selectors.
 
 use dom::{Node, NodeType, ElementData};
-use css::{Stylesheet, Rule, Selector, SimpleSelector, Value, Specificity};
+use css::{Stylesheet, CssItem, Rule, Selector, SimpleSelector, AttrSelector, AttrOp, PseudoClass, Combinator, Value, Specificity};
+use bloom::BloomFilter;
 use std::collections::HashMap;
 
 /*
-The first step in building the style tree is selector
-matching. This will be very easy, since the CSS parser
-supports only simple selectors. You can tell whether a
-simple selector matches an element just by looking at the
-element itself. Matching compound selectors would require
-traversing the DOM tree to look at the element's siblings,
-parents and so on.
+Now that the CSS parser supports compound selectors (`div.note p`,
+`ul > li`), matching a selector against an element can require walking
+up the tree to look at its ancestors. We match right-to-left: the
+rightmost simple selector is tested against the element itself, and
+only once that matches do we walk upward looking for ancestors that
+satisfy the remaining parts.
+
+Most of the time that ancestor walk fails because some required
+ancestor id/class/tag simply isn't anywhere on the path to the root.
+Before paying for the (possibly backtracking) walk, we consult a
+`BloomFilter` of the atoms seen on the current ancestor path: if it
+tells us an atom the selector requires is definitely absent, we can
+reject the selector without walking anything.
  */
 
-fn matches(elem: &ElementData, selector: &Selector) -> bool {
+/// An ancestor on the path from the root down to (but not including)
+/// the element being matched, together with its 1-based position among
+/// its own element siblings and the total number of element siblings
+/// it has — everything a structural pseudo-class on an ancestor-side
+/// simple selector needs, computed once per node as `style_tree` walks
+/// down rather than recomputed per selector.
+#[deriving(Clone)]
+struct AncestorInfo<'a> {
+	elem: &'a ElementData,
+	index: uint,
+	count: uint,
+}
+
+fn matches(elem: &ElementData, index: uint, count: uint, ancestors: &[AncestorInfo], filter: &BloomFilter, selector: &Selector) -> bool {
 	match *selector {
-		Simple(ref simple_selector) => matches_simple_selector(elem, simple_selector)
+		Selector::Simple(ref simple_selector) => matches_simple_selector(elem, index, count, simple_selector),
+		Selector::Compound { ref parts, ref combinators } =>
+			matches_compound_selector(elem, index, count, ancestors, filter, parts, combinators),
+	}
+}
+
+/// Match a compound selector's rightmost part against `elem`, then walk
+/// `ancestors` (nearest-ancestor-last) to satisfy the remaining parts.
+fn matches_compound_selector(elem: &ElementData,
+                              index: uint,
+                              count: uint,
+                              ancestors: &[AncestorInfo],
+                              filter: &BloomFilter,
+                              parts: &[SimpleSelector],
+                              combinators: &[Combinator]) -> bool {
+	let last = parts.len() - 1;
+	if !matches_simple_selector(elem, index, count, &parts[last]) {
+		return false;
+	}
+	if !ancestor_atoms_possible(filter, &parts[..last]) {
+		// Some id/class/tag the remaining parts require isn't on the
+		// ancestor path at all, so the walk below cannot possibly
+		// succeed.
+		return false;
+	}
+	matches_ancestor_chain(ancestors, parts, combinators, last)
+}
+
+/// Quick pre-check: for every ancestor-side simple selector, make sure
+/// the Bloom filter doesn't rule out every id/class/tag it requires.
+/// A `false` result means the full ancestor walk would be wasted work.
+fn ancestor_atoms_possible(filter: &BloomFilter, ancestor_parts: &[SimpleSelector]) -> bool {
+	for part in ancestor_parts.iter() {
+		if let Some(ref id) = part.id {
+			if filter.definitely_absent(id.as_slice()) {
+				return false;
+			}
+		}
+		for class in part.class.iter() {
+			if filter.definitely_absent(class.as_slice()) {
+				return false;
+			}
+		}
+		if let Some(ref tag_name) = part.tag_name {
+			if filter.definitely_absent(tag_name.as_slice()) {
+				return false;
+			}
+		}
+	}
+	true
+}
+
+/// Try to match `parts[0 .. part_index]` against `ancestors`, where
+/// `combinators[i]` joins `parts[i]` to `parts[i + 1]`.
+fn matches_ancestor_chain(ancestors: &[AncestorInfo],
+                           parts: &[SimpleSelector],
+                           combinators: &[Combinator],
+                           part_index: uint) -> bool {
+	if part_index == 0 {
+		// Every part of the selector has already been satisfied.
+		return true;
+	}
+
+	let target = &parts[part_index - 1];
+	match combinators[part_index - 1] {
+		Combinator::Child => {
+			// Only the immediate parent may satisfy a child combinator.
+			match ancestors.last() {
+				Some(parent) =>
+					matches_simple_selector(parent.elem, parent.index, parent.count, target) &&
+					matches_ancestor_chain(ancestors.slice_to(ancestors.len() - 1), parts, combinators, part_index - 1),
+				None => false
+			}
+		}
+		Combinator::Descendant => {
+			// Any ancestor may satisfy a descendant combinator; backtrack
+			// through each candidate (nearest first) until one works.
+			let mut i = ancestors.len();
+			while i > 0 {
+				i -= 1;
+				if matches_simple_selector(ancestors[i].elem, ancestors[i].index, ancestors[i].count, target) &&
+				   matches_ancestor_chain(ancestors.slice_to(i), parts, combinators, part_index - 1) {
+					return true;
+				}
+			}
+			false
+		}
 	}
 }
 
 /*
-To help, we'll add some convenient ID and class accessors 
+To help, we'll add some convenient ID and class accessors
 to our DOM element type. The class attribute can contain
-multiple class names separated by spaces, which we return 
+multiple class names separated by spaces, which we return
 in a hash table.
  */
 
@@ -36,19 +142,21 @@ impl ElementData {
 	}
 
 	pub fn classes(&self) -> HashSet<&str> {
-		Some(classlist) => classlist.as_slice().split(' ').collect(),
-		None => HashSet::new()
+		match self.attributes.get("class") {
+			Some(classlist) => classlist.as_slice().split(' ').collect(),
+			None => HashSet::new()
+		}
 	}
 }
 
 /*
-To test whether a simple selector matches an 
+To test whether a simple selector matches an
 element, just look at each selector component.
-Return false if the element doesn't have a 
+Return false if the element doesn't have a
 matching class, ID or tag name.
  */
 
-fn matches_simple_selector(elem: &ElementData, selector: &SimpleSelector) -> bool {
+fn matches_simple_selector(elem: &ElementData, index: uint, count: uint, selector: &SimpleSelector) -> bool {
 	// Check type selector
 	if selector.tag_name.iter().any(|name| elem.tag_name != *name) {
 		return false;
@@ -65,60 +173,218 @@ fn matches_simple_selector(elem: &ElementData, selector: &SimpleSelector) -> boo
 		return false;
 	}
 
+	// Check attribute selectors
+	if selector.attrs.iter().any(|attr| !matches_attr_selector(elem, attr)) {
+		return false;
+	}
+
+	// Check structural pseudo-classes
+	if selector.pseudo.iter().any(|pseudo| !matches_pseudo_class(index, count, pseudo)) {
+		return false;
+	}
+
 	// We didn't find any non-matching selector components.
 	return true;
 }
 
+/// Test a single structural pseudo-class against an element's 1-based
+/// `index` among its element siblings and their total `count`.
+fn matches_pseudo_class(index: uint, count: uint, pseudo: &PseudoClass) -> bool {
+	match *pseudo {
+		PseudoClass::FirstChild => index == 1,
+		PseudoClass::LastChild => index == count,
+		PseudoClass::NthChild { a, b } => nth_child_matches(index, a, b),
+	}
+}
+
+/// `:nth-child(an+b)` matches when there's a non-negative integer `n`
+/// with `index == a*n + b`.
+fn nth_child_matches(index: uint, a: i32, b: i32) -> bool {
+	let diff = index as i32 - b;
+	if a == 0 {
+		diff == 0
+	} else {
+		diff % a == 0 && diff / a >= 0
+	}
+}
+
+/// Test a single `[attr...]` condition against an element.
+fn matches_attr_selector(elem: &ElementData, attr: &AttrSelector) -> bool {
+	let actual = match elem.attributes.get(&attr.name) {
+		Some(value) => value,
+		None => return false,
+	};
+
+	match attr.op {
+		AttrOp::Presence => true,
+		AttrOp::Exact => Some(actual) == attr.value.as_ref(),
+		AttrOp::Includes => {
+			let wanted = attr.value.as_ref().unwrap();
+			actual.as_slice().split(' ').any(|word| word == wanted.as_slice())
+		}
+		AttrOp::Prefix => actual.as_slice().starts_with(attr.value.as_ref().unwrap().as_slice()),
+		AttrOp::Suffix => actual.as_slice().ends_with(attr.value.as_ref().unwrap().as_slice()),
+		AttrOp::Substring => actual.as_slice().contains(attr.value.as_ref().unwrap().as_slice()),
+	}
+}
+
 // The above uses the any method which returns true
 // if an iterator contains an element that passes the
 // provided test. This is the same as the any function in
 // Python.
 
 // Next we need to traverse the DOM tree. For each element
-// in the tree, we will search the stylesheet for matching 
-// rules.
-// 
+// in the tree, we will search the stylesheet for matching
+// rules, via the `Stylist` below.
+//
 // When comparing two rules that match the same element, we
 // need ti yse the highest-specificty selector from each match.
 // Because our CSS parser stores the selectors from most-to
 // least-specific, we can stop as soon as we find a matching
 // one and return its specificity along with a pointer to the rule.
-// 
+//
 
-type MatchedRule<'a> = (Specificity, &'a Rule);
+type MatchedRule<'a> = (Specificity, uint, &'a Rule);
+
+/*
+Rather than rescanning every rule in the stylesheet for every element,
+a `Stylist` is built once per stylesheet and buckets each selector by
+the most distinguishing component of its *rightmost* simple selector:
+an id, if it has one; otherwise a class; otherwise its tag name;
+otherwise it falls into the `universal` bucket. Looking up candidates
+for an element is then just a handful of hash lookups (one per id,
+class and tag the element has) instead of a scan of every rule.
+ */
 
-/// If `rule` matches `elem`, return a `MatchedRule`.
-/// Otherwise return `None`.
-fn match_rule<'a>(elem: &ElementData, rule: &'a Rule) -> Option<MatchedRule<'a>> {
-	// Find the first (highest-specificity) matching selector.
-	rule.selectors.iter().find(|selector| matches(elem, *selector)).map(|selector| (selector.specificity(), rule))
+/// A single selector from a rule, indexed by the `Stylist`, along with
+/// the rule it belongs to and that rule's position in the stylesheet
+/// (used to break specificity ties in source order).
+struct StylistEntry<'a> {
+	selector: &'a Selector,
+	rule: &'a Rule,
+	source_order: uint,
+}
+
+pub struct Stylist<'a> {
+	id_map: HashMap<String, Vec<StylistEntry<'a>>>,
+	class_map: HashMap<String, Vec<StylistEntry<'a>>>,
+	tag_map: HashMap<String, Vec<StylistEntry<'a>>>,
+	universal: Vec<StylistEntry<'a>>,
+}
+
+/// The rightmost simple selector of a (possibly compound) selector is
+/// the one matched directly against the element, so it's what we index
+/// on: an element can only match a rule if it has the id/class/tag that
+/// rule's rightmost part requires.
+fn rightmost_simple_selector(selector: &Selector) -> &SimpleSelector {
+	match *selector {
+		Selector::Simple(ref simple) => simple,
+		Selector::Compound { ref parts, .. } => &parts[parts.len() - 1],
+	}
 }
 
-/// To find all the rules that match an element we call filter_map
-/// which does a linear scan through the style sheet, checking every
-/// rule and throwing out ones that don't match. A real browser engine 
-/// would speed this up by storing the rules in multiple hash tables
-/// based on tag name, id, class, etc.
+impl<'a> Stylist<'a> {
+	/// Build a `Stylist` from a stylesheet for the given media context
+	/// (e.g. `"screen"` or `"print"`), bucketing every selector of
+	/// every applicable rule by its rightmost simple selector's most
+	/// distinguishing component.
+	///
+	/// `@media` blocks whose query doesn't match `media_context` are
+	/// skipped entirely, as if their rules didn't exist. `@import`
+	/// items are ignored here — a caller is expected to have already
+	/// resolved and spliced imported stylesheets into `items` before
+	/// building the `Stylist`.
+	pub fn new(stylesheet: &'a Stylesheet, media_context: &str) -> Stylist<'a> {
+		let mut stylist = Stylist {
+			id_map: HashMap::new(),
+			class_map: HashMap::new(),
+			tag_map: HashMap::new(),
+			universal: Vec::new(),
+		};
+
+		let mut source_order = 0u;
+		for item in stylesheet.items.iter() {
+			match *item {
+				CssItem::Qualified(ref rule) => {
+					stylist.add_rule(rule, source_order);
+					source_order += 1;
+				}
+				CssItem::Media(ref media_rule) => {
+					if media_rule.query.as_slice() == media_context {
+						for rule in media_rule.rules.iter() {
+							stylist.add_rule(rule, source_order);
+							source_order += 1;
+						}
+					}
+				}
+				CssItem::Import(_) => {
+					// Resolved (or not) by the caller before the
+					// Stylist is built; nothing to do here.
+				}
+			}
+		}
+
+		stylist
+	}
+
+	fn add_rule(&mut self, rule: &'a Rule, source_order: uint) {
+		for selector in rule.selectors.iter() {
+			let entry = StylistEntry { selector: selector, rule: rule, source_order: source_order };
+			let simple = rightmost_simple_selector(selector);
+			if let Some(ref id) = simple.id {
+				self.id_map.entry(id.clone()).or_insert_with(Vec::new).push(entry);
+			} else if let Some(class) = simple.class.first() {
+				self.class_map.entry(class.clone()).or_insert_with(Vec::new).push(entry);
+			} else if let Some(ref tag_name) = simple.tag_name {
+				self.tag_map.entry(tag_name.clone()).or_insert_with(Vec::new).push(entry);
+			} else {
+				self.universal.push(entry);
+			}
+		}
+	}
+
+	/// Find all CSS rules that match the given element, by looking only
+	/// at the buckets its id, classes and tag name could possibly be in.
+	fn matching_rules(&self, elem: &ElementData, index: uint, count: uint, ancestors: &[AncestorInfo], filter: &BloomFilter) -> Vec<MatchedRule<'a>> {
+		let mut candidates: Vec<&StylistEntry<'a>> = Vec::new();
+
+		if let Some(id) = elem.id() {
+			if let Some(entries) = self.id_map.get(id) {
+				candidates.extend(entries.iter());
+			}
+		}
+		for class in elem.classes().iter() {
+			if let Some(entries) = self.class_map.get(*class) {
+				candidates.extend(entries.iter());
+			}
+		}
+		if let Some(entries) = self.tag_map.get(&elem.tag_name) {
+			candidates.extend(entries.iter());
+		}
+		candidates.extend(self.universal.iter());
 
-/// Find all CSS rules that match the given element.
-fn matching_rules<'a>(elem: &ElementData, stylesheet: &'a Stylesheet) -> Vec<MatchedRule<'a>> {
-	stylesheet.rules.iter().filter_map(|rule| match_rule(elem, rule)).collect()
+		candidates.iter()
+			.filter(|entry| matches(elem, index, count, ancestors, filter, entry.selector))
+			.map(|entry| (entry.selector.specificity(), entry.source_order, entry.rule))
+			.collect()
+	}
 }
 
 /// Once we have the matching rules, we can find the specified
 /// values for the element. We insert each rule's property values into
-/// a HashMap. We sort the matches by specificity, so the more
-/// specific rules are processed after the less specific ones and can
-/// overwrite their values in the HashMap.
+/// a HashMap. We sort the matches by (specificity, source order), so
+/// the more specific (and, among equally specific, later) rules are
+/// processed after the less specific ones and can overwrite their
+/// values in the HashMap.
 
 /// Apply styles to a single element, returning the specified values.
-fn specified_values(elem: &ElementData, style: &Stylesheet) -> PropertyMap {
+fn specified_values(elem: &ElementData, index: uint, count: uint, ancestors: &[AncestorInfo], filter: &BloomFilter, stylist: &Stylist) -> PropertyMap {
 	let mut values = HashMap::new();
-	let mut rules  = matching_rules(elem, stylesheet);
+	let mut rules  = stylist.matching_rules(elem, index, count, ancestors, filter);
 
-	// Go through the rules from lowest to highest specificity
-	rules.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
-	for &(_, rule) in rules.iter() {
+	// Go through the rules from lowest to highest (specificity, source order)
+	rules.sort_by(|&(spec_a, order_a, _), &(spec_b, order_b, _)| (spec_a, order_a).cmp(&(spec_b, order_b)));
+	for &(_, _, rule) in rules.iter() {
 		for declaration in rule.declarations.iter() {
 			values.insert(declaration.name.clone(), declaration.value.clone());
 		}
@@ -128,46 +394,81 @@ fn specified_values(elem: &ElementData, style: &Stylesheet) -> PropertyMap {
 }
 
 /// Now we have everything we need to walk through the DOM
-/// tree and build the style tree. Note that selector matching 
-/// works only on elements, so the specified values for a text 
+/// tree and build the style tree. Note that selector matching
+/// works only on elements, so the specified values for a text
 /// node are just an empty map.
+///
+/// `ancestors` holds the chain of `AncestorInfo` from the root down to
+/// (but not including) `root`, nearest ancestor last. Matching a
+/// compound selector's leading parts against an element's ancestors
+/// requires this chain, so we thread it through the recursion,
+/// appending `root`'s own entry before recursing into its children.
+/// `filter` is a `BloomFilter` of every id/class/tag on that same
+/// ancestor path, maintained in lock-step: we insert `root`'s own
+/// atoms before recursing into its children, and remove them again
+/// once we're done, so that at every point in the recursion the filter
+/// reflects exactly the atoms on the path from the root down to the
+/// current node's parent. `index`/`count` are `root`'s own 1-based
+/// position among its element siblings and their total number — computed
+/// once by the parent call and handed down, rather than recomputed for
+/// every selector that might need it.
+
+/// Apply a stylesheet (via its `Stylist`) to an entire DOM tree,
+/// returning a StyledNode tree.
+pub fn style_tree<'a>(root: &'a Node, stylist: &Stylist<'a>, ancestors: &[AncestorInfo<'a>], index: uint, count: uint, filter: &mut BloomFilter) -> StyledNode<'a> {
+	let specified_values = match root.node_type {
+		Element(ref elem) => specified_values(elem, index, count, ancestors, filter, stylist),
+		Text(_) => HashMap::new()
+	};
+
+	let mut child_ancestors: Vec<AncestorInfo<'a>> = ancestors.to_vec();
+	let pushed_atoms: Vec<String> = match root.node_type {
+		Element(ref elem) => {
+			child_ancestors.push(AncestorInfo { elem: elem, index: index, count: count });
+			let mut atoms = Vec::new();
+			if let Some(id) = elem.id() {
+				filter.insert(id.as_slice());
+				atoms.push(id.clone());
+			}
+			for class in elem.classes().iter() {
+				filter.insert(*class);
+				atoms.push(class.to_string());
+			}
+			filter.insert(elem.tag_name.as_slice());
+			atoms.push(elem.tag_name.clone());
+			atoms
+		}
+		Text(_) => Vec::new()
+	};
+
+	// Each element child's 1-based position among *element* siblings,
+	// and their total count, computed once here rather than recomputed
+	// for every selector that turns out to need it further down.
+	let child_count = root.children.iter().filter(|child| is_element(*child)).count();
+	let mut child_index = 0u;
+	let children = root.children.iter().map(|child| {
+		if is_element(child) {
+			child_index += 1;
+		}
+		style_tree(child, stylist, child_ancestors.as_slice(), child_index, child_count, filter)
+	}).collect();
+
+	// Leaving this subtree: remove what we pushed so siblings of our
+	// ancestors don't see atoms from our branch.
+	for atom in pushed_atoms.iter() {
+		filter.remove(atom.as_slice());
+	}
 
-/// Apply a stylesheet to an entire DOM tree, returning a StyledNode tree.
-pub fn style_tree<'a>(root: &'a Node, stylesheet: &'a Stylesheet) -> StyledNode<'a> {
 	StyledNode {
 		node: root,
-		specified_values: match root.node_type {
-			Element(ref elem) => specified_values(elem, stylesheet),
-			Text(_) => HashMap::new()
-		},
-		children: root.children.iter().map(|child| style_tree(child, stylesheet)).collect(),
+		specified_values: specified_values,
+		children: children,
 	}
 }
 
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
+fn is_element(node: &Node) -> bool {
+	match node.node_type {
+		Element(_) => true,
+		Text(_) => false,
+	}
+}